@@ -3,42 +3,173 @@
 //! 通过 Unix Socket 接收图片，转换为 WebP 格式后返回
 //! 协议: [4字节长度(大端)][数据]
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use bytes::{BufMut, BytesMut};
+use futures::{SinkExt, StreamExt};
+use image::imageops::FilterType;
+use serde::{Deserialize, Serialize};
 use tokio::net::UnixListener;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
 const SOCKET_PATH: &str = "/tmp/img-processor.sock";
 const MAX_IMAGE_SIZE: usize = 10 * 1024 * 1024; // 10MB
 
-#[tokio::main(flavor = "current_thread")]
+/// 成功转换的累计统计，HTTP 模式下通过 `/stats` 暴露。
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// 成功转换的图片数
+    conversions: AtomicUsize,
+    /// 累计读入的原始字节数
+    bytes_in: AtomicUsize,
+    /// 累计输出的字节数
+    bytes_out: AtomicUsize,
+}
+
+impl Stats {
+    fn record(&self, bytes_in: usize, bytes_out: usize) {
+        self.conversions.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes_in, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes_out, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> serde_json::Value {
+        serde_json::json!({
+            "conversions": self.conversions.load(Ordering::Relaxed),
+            "bytes_in": self.bytes_in.load(Ordering::Relaxed),
+            "bytes_out": self.bytes_out.load(Ordering::Relaxed),
+        })
+    }
+}
+
+/// 目标编码格式。与 `Message` 风格一致，用带数据的枚举承载每种格式的参数。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TargetFormat {
+    /// 有损 WebP，quality 取值 0.0-100.0
+    WebpLossy { quality: f32 },
+    /// 无损 WebP
+    WebpLossless,
+    /// JPEG，quality 取值 1-100
+    Jpeg { quality: u8 },
+    /// PNG
+    Png,
+}
+
+impl Default for TargetFormat {
+    fn default() -> Self {
+        // 旧客户端发默认信封即得到原先的「有损 WebP、质量 85」行为
+        TargetFormat::WebpLossy { quality: 85.0 }
+    }
+}
+
+/// 转换请求信封：作为图片数据前的第一帧发送。
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConvertRequest {
+    /// 目标格式
+    #[serde(default)]
+    pub format: TargetFormat,
+    /// 可选的最大宽度，超出时按比例缩放
+    #[serde(default)]
+    pub max_width: Option<u32>,
+    /// 可选的最大高度，超出时按比例缩放
+    #[serde(default)]
+    pub max_height: Option<u32>,
+    /// 是否丢弃 alpha 通道
+    #[serde(default)]
+    pub strip_alpha: bool,
+    /// 可选的文字/水印叠加，在编码前绘制
+    #[serde(default)]
+    pub overlay: Option<Overlay>,
+}
+
+/// 文字叠加锚点：四角或居中。
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Anchor {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+impl Default for Anchor {
+    fn default() -> Self {
+        Anchor::BottomRight
+    }
+}
+
+/// 文字/水印叠加参数。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Overlay {
+    /// 水印文字
+    pub caption: String,
+    /// 字号 (像素)
+    pub font_size: f32,
+    /// RGBA 颜色
+    pub color: [u8; 4],
+    /// 锚点位置
+    #[serde(default)]
+    pub anchor: Anchor,
+}
+
+/// 内嵌的 TrueType 字体，用于文字叠加。
+const FONT_DATA: &[u8] = include_bytes!("../assets/DejaVuSans.ttf");
+
+/// 边缘留白 (像素)，避免文字紧贴图片边界。
+const OVERLAY_MARGIN: i32 = 8;
+
+#[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // HTTP 模式: `--http <addr>` 或环境变量 IMG_PROCESSOR_HTTP。
+    // 设置后与 Unix socket 并行监听，本地 IPC 依旧可用。
+    let http = http_addr();
+
     // 删除旧的 socket 文件
     let _ = std::fs::remove_file(SOCKET_PATH);
-    
+
     let listener = UnixListener::bind(SOCKET_PATH)?;
     println!("[img-processor] Listening on {}", SOCKET_PATH);
-    
+
     // 设置权限
     #[cfg(unix)]
     {
         use std::os::unix::fs::PermissionsExt;
         std::fs::set_permissions(SOCKET_PATH, std::fs::Permissions::from_mode(0o666))?;
     }
-    
+
     // 优雅关闭
     let shutdown = async {
         tokio::signal::ctrl_c().await.ok();
         println!("\n[img-processor] Shutting down...");
     };
-    
+
     tokio::select! {
         _ = accept_loop(&listener) => {}
+        res = serve_http(http) => {
+            if let Err(e) = res {
+                eprintln!("[img-processor] HTTP error: {}", e);
+            }
+        }
         _ = shutdown => {}
     }
-    
+
     let _ = std::fs::remove_file(SOCKET_PATH);
     Ok(())
 }
 
+/// 解析 HTTP 监听地址：优先命令行 `--http <addr>`，其次环境变量。
+fn http_addr() -> Option<String> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--http" {
+            return args.next();
+        }
+    }
+    std::env::var("IMG_PROCESSOR_HTTP").ok()
+}
+
 async fn accept_loop(listener: &UnixListener) {
     loop {
         match listener.accept().await {
@@ -52,69 +183,539 @@ async fn accept_loop(listener: &UnixListener) {
     }
 }
 
-async fn handle_connection(mut stream: tokio::net::UnixStream) {
-    // 读取长度 (4 字节大端)
-    let mut len_buf = [0u8; 4];
-    if stream.read_exact(&mut len_buf).await.is_err() {
-        return;
+/// HTTP 监听：接收 POST 图片，返回 WebP，并在 `/stats` 暴露统计。
+/// `addr` 为 `None` 时 (未开启 HTTP 模式) 永远挂起，不干扰 Unix socket。
+async fn serve_http(addr: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::Server;
+
+    let addr = match addr {
+        Some(addr) => addr.parse()?,
+        None => return std::future::pending().await,
+    };
+    let stats = Arc::new(Stats::default());
+
+    let make_svc = make_service_fn(move |_conn| {
+        let stats = stats.clone();
+        async move {
+            Ok::<_, std::convert::Infallible>(service_fn(move |req| {
+                http_handler(req, stats.clone())
+            }))
+        }
+    });
+
+    println!("[img-processor] HTTP listening on {}", addr);
+    Server::bind(&addr).serve(make_svc).await?;
+    Ok(())
+}
+
+async fn http_handler(
+    req: hyper::Request<hyper::Body>,
+    stats: Arc<Stats>,
+) -> Result<hyper::Response<hyper::Body>, hyper::Error> {
+    use hyper::{Method, Response, StatusCode};
+
+    let started = Instant::now();
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+
+    // /stats: 返回 JSON 统计
+    if method == Method::GET && path == "/stats" {
+        let body = stats.snapshot().to_string();
+        return Ok(Response::builder()
+            .header("Content-Type", "application/json")
+            .body(hyper::Body::from(body))
+            .unwrap());
+    }
+
+    // 其余路径: POST 图片体，转换为 WebP
+    if method != Method::POST {
+        return Ok(text_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"));
     }
-    let len = u32::from_be_bytes(len_buf) as usize;
-    
-    // 验证大小
-    if len == 0 || len > MAX_IMAGE_SIZE {
-        let _ = send_error(&mut stream, "Invalid size").await;
-        return;
+
+    // multipart/form-data: 浏览器 <form> 与 `curl -F` 直接可用
+    if let Some(boundary) = req
+        .headers()
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|ct| multer::parse_boundary(ct).ok())
+    {
+        return handle_multipart(req, stats, boundary, &method, &path, started).await;
+    }
+
+    // 先看 Content-Length 提前拒绝，再带上限累积读取，避免超大上传撑爆内存
+    if let Some(len) = req
+        .headers()
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok())
+    {
+        if len > MAX_IMAGE_SIZE {
+            return Ok(text_response(StatusCode::PAYLOAD_TOO_LARGE, "Invalid size"));
+        }
+    }
+
+    let data = match read_capped_body(req.into_body(), MAX_IMAGE_SIZE).await? {
+        Some(data) => data,
+        None => return Ok(text_response(StatusCode::PAYLOAD_TOO_LARGE, "Invalid size")),
+    };
+    let source_size = data.len();
+    if source_size == 0 {
+        return Ok(text_response(StatusCode::BAD_REQUEST, "Invalid size"));
+    }
+
+    let result = tokio::task::spawn_blocking(move || {
+        process_image(&data, &ConvertRequest::default())
+    })
+    .await;
+
+    let response = match result {
+        Ok(Ok(webp_data)) => {
+            let output_size = webp_data.len();
+            stats.record(source_size, output_size);
+            eprintln!(
+                "[img-processor] {} {} in={} out={} elapsed={:?}",
+                method, path, source_size, output_size, started.elapsed()
+            );
+            Response::builder()
+                .header("Content-Type", "image/webp")
+                .body(hyper::Body::from(webp_data))
+                .unwrap()
+        }
+        Ok(Err(e)) => text_response(StatusCode::UNPROCESSABLE_ENTITY, &e),
+        Err(e) => text_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("Encode task failed: {}", e),
+        ),
+    };
+    Ok(response)
+}
+
+/// 处理 multipart/form-data 上传：每个文件 part 独立解码并重编码为 WebP。
+/// 单个文件时直接返回 WebP，多个文件时返回字段名到输出长度的 JSON 清单。
+async fn handle_multipart(
+    req: hyper::Request<hyper::Body>,
+    stats: Arc<Stats>,
+    boundary: String,
+    method: &hyper::Method,
+    path: &str,
+    started: Instant,
+) -> Result<hyper::Response<hyper::Body>, hyper::Error> {
+    use hyper::{Response, StatusCode};
+
+    let mut multipart = multer::Multipart::new(req.into_body(), boundary);
+
+    // 每个 part 的转换结果: (字段名, WebP 数据)
+    let mut outputs: Vec<(String, Vec<u8>)> = Vec::new();
+
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return Ok(text_response(StatusCode::BAD_REQUEST, &e.to_string())),
+        };
+
+        // 只处理带文件名的文件 part
+        if field.file_name().is_none() {
+            continue;
+        }
+        let name = field.name().unwrap_or("file").to_string();
+
+        // 逐块累积并提前拒绝超限的 part
+        let mut field = field;
+        let mut data = Vec::new();
+        loop {
+            match field.chunk().await {
+                Ok(Some(chunk)) => {
+                    if data.len() + chunk.len() > MAX_IMAGE_SIZE {
+                        return Ok(text_response(
+                            StatusCode::PAYLOAD_TOO_LARGE,
+                            &format!("Part '{}' exceeds max size", name),
+                        ));
+                    }
+                    data.extend_from_slice(&chunk);
+                }
+                Ok(None) => break,
+                Err(e) => return Ok(text_response(StatusCode::BAD_REQUEST, &e.to_string())),
+            }
+        }
+
+        if data.is_empty() {
+            continue;
+        }
+
+        let source_size = data.len();
+        let result =
+            tokio::task::spawn_blocking(move || process_image(&data, &ConvertRequest::default()))
+                .await;
+        match result {
+            Ok(Ok(webp_data)) => {
+                stats.record(source_size, webp_data.len());
+                eprintln!(
+                    "[img-processor] {} {} field={} in={} out={} elapsed={:?}",
+                    method, path, name, source_size, webp_data.len(), started.elapsed()
+                );
+                outputs.push((name, webp_data));
+            }
+            // 非图片或解码失败的 part 提前拒绝
+            Ok(Err(e)) => {
+                return Ok(text_response(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    &format!("Part '{}': {}", name, e),
+                ))
+            }
+            Err(e) => {
+                return Ok(text_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &format!("Encode task failed: {}", e),
+                ))
+            }
+        }
     }
-    
-    // 读取图片数据
-    let mut data = vec![0u8; len];
-    if stream.read_exact(&mut data).await.is_err() {
-        let _ = send_error(&mut stream, "Read failed").await;
-        return;
+
+    match outputs.len() {
+        0 => Ok(text_response(StatusCode::BAD_REQUEST, "No image parts")),
+        // 单文件: 直接返回 WebP
+        1 => {
+            let (_, webp_data) = outputs.pop().unwrap();
+            Ok(Response::builder()
+                .header("Content-Type", "image/webp")
+                .body(hyper::Body::from(webp_data))
+                .unwrap())
+        }
+        // 多文件: 返回字段名 -> 输出长度的清单
+        _ => {
+            let manifest: serde_json::Map<String, serde_json::Value> = outputs
+                .into_iter()
+                .map(|(name, data)| (name, serde_json::json!(data.len())))
+                .collect();
+            Ok(Response::builder()
+                .header("Content-Type", "application/json")
+                .body(hyper::Body::from(serde_json::Value::Object(manifest).to_string()))
+                .unwrap())
+        }
     }
-    
-    // 处理图片
-    match process_image(&data) {
-        Ok(webp_data) => {
-            let _ = send_response(&mut stream, &webp_data).await;
+}
+
+/// 按块累积请求体，超过 `cap` 立即返回 `Ok(None)`，避免把超大上传整个读进内存。
+async fn read_capped_body(mut body: hyper::Body, cap: usize) -> Result<Option<Vec<u8>>, hyper::Error> {
+    use hyper::body::HttpBody;
+
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if data.len() + chunk.len() > cap {
+            return Ok(None);
         }
-        Err(e) => {
-            let _ = send_error(&mut stream, &e).await;
+        data.extend_from_slice(&chunk);
+    }
+    Ok(Some(data))
+}
+
+fn text_response(status: hyper::StatusCode, msg: &str) -> hyper::Response<hyper::Body> {
+    hyper::Response::builder()
+        .status(status)
+        .header("Content-Type", "text/plain")
+        .body(hyper::Body::from(msg.to_string()))
+        .unwrap()
+}
+
+async fn handle_connection(stream: tokio::net::UnixStream) {
+    // 长度分帧编解码器: 4 字节大端长度前缀 + 数据，复用 MAX_IMAGE_SIZE 作为上限
+    let codec = LengthDelimitedCodec::builder()
+        .length_field_type::<u32>()
+        .max_frame_length(MAX_IMAGE_SIZE)
+        .new_codec();
+    let mut framed = Framed::new(stream, codec);
+
+    // 单连接内可连续发送多张图片，每次转换 = 信封帧 + 图片帧，
+    // 省去反复 connect/accept 的开销
+    while let Some(frame) = framed.next().await {
+        // 第一帧：转换信封
+        let envelope = match frame {
+            Ok(buf) => buf,
+            // 帧超限或 I/O 出错，无法再信任这条连接，直接结束
+            Err(_) => break,
+        };
+        let request: ConvertRequest = match serde_json::from_slice(&envelope) {
+            Ok(req) => req,
+            Err(e) => {
+                let _ = send_error(&mut framed, &format!("Invalid request: {}", e)).await;
+                // 每次转换占两帧，丢弃配对的图片帧，避免把图片字节当成下一个信封解析
+                match framed.next().await {
+                    Some(Ok(_)) => continue,
+                    _ => break,
+                }
+            }
+        };
+
+        // 第二帧：图片数据
+        let data = match framed.next().await {
+            Some(Ok(buf)) if !buf.is_empty() => buf,
+            Some(Ok(_)) => {
+                let _ = send_error(&mut framed, "Invalid size").await;
+                continue;
+            }
+            _ => break,
+        };
+
+        // 处理图片 (CPU 密集，放到阻塞线程池，避免卡住异步执行器)
+        let data = data.to_vec();
+        let result = tokio::task::spawn_blocking(move || process_image(&data, &request)).await;
+        let sent = match result {
+            Ok(Ok(webp_data)) => send_response(&mut framed, &webp_data).await,
+            Ok(Err(e)) => send_error(&mut framed, &e).await,
+            // 编码任务 panic，走状态码 1 的错误路径
+            Err(e) => send_error(&mut framed, &format!("Encode task failed: {}", e)).await,
+        };
+        if sent.is_err() {
+            break;
         }
     }
 }
 
-fn process_image(data: &[u8]) -> Result<Vec<u8>, String> {
+type FramedStream = Framed<tokio::net::UnixStream, LengthDelimitedCodec>;
+
+fn process_image(data: &[u8], request: &ConvertRequest) -> Result<Vec<u8>, String> {
     // 解码图片
-    let img = image::load_from_memory(data)
+    let mut img = image::load_from_memory(data)
         .map_err(|e| format!("Decode failed: {}", e))?;
-    
-    // 转换为 RGBA8
-    let rgba = img.to_rgba8();
-    let (width, height) = rgba.dimensions();
-    
-    // 编码为 WebP (有损，质量 85)
-    let encoder = webp::Encoder::from_rgba(&rgba, width, height);
-    let webp_data = encoder.encode(85.0);
-    
+
+    // 按需缩放 (Lanczos3)，保持比例限制在给定宽高内。
+    // "max" 语义只缩小不放大：把目标夹到原始尺寸，已经在范围内则跳过。
+    if request.max_width.is_some() || request.max_height.is_some() {
+        let (w, h) = (img.width(), img.height());
+        let target_w = request.max_width.unwrap_or(w).min(w);
+        let target_h = request.max_height.unwrap_or(h).min(h);
+        if target_w < w || target_h < h {
+            img = img.resize(target_w, target_h, FilterType::Lanczos3);
+        }
+    }
+
+    // 可选文字/水印叠加，在编码前一次性绘制
+    if let Some(overlay) = &request.overlay {
+        img = draw_overlay(img, overlay)?;
+    }
+
+    match request.format {
+        TargetFormat::WebpLossy { quality } => encode_webp(&img, Some(quality), request.strip_alpha),
+        TargetFormat::WebpLossless => encode_webp(&img, None, request.strip_alpha),
+        TargetFormat::Jpeg { quality } => encode_image(&img, request, ImageFormat::Jpeg(quality)),
+        TargetFormat::Png => encode_image(&img, request, ImageFormat::Png),
+    }
+}
+
+/// 按锚点计算文字左上角坐标，并夹紧到 [0, 尺寸 - 文字] 以免越界。
+fn anchor_position(img_w: u32, img_h: u32, text_w: u32, text_h: u32, anchor: Anchor) -> (i32, i32) {
+    let (raw_x, raw_y) = match anchor {
+        Anchor::TopLeft => (OVERLAY_MARGIN, OVERLAY_MARGIN),
+        Anchor::TopRight => (img_w as i32 - text_w as i32 - OVERLAY_MARGIN, OVERLAY_MARGIN),
+        Anchor::BottomLeft => (OVERLAY_MARGIN, img_h as i32 - text_h as i32 - OVERLAY_MARGIN),
+        Anchor::BottomRight => (
+            img_w as i32 - text_w as i32 - OVERLAY_MARGIN,
+            img_h as i32 - text_h as i32 - OVERLAY_MARGIN,
+        ),
+        Anchor::Center => (
+            (img_w as i32 - text_w as i32) / 2,
+            (img_h as i32 - text_h as i32) / 2,
+        ),
+    };
+    let x = raw_x.clamp(0, (img_w as i32 - text_w as i32).max(0));
+    let y = raw_y.clamp(0, (img_h as i32 - text_h as i32).max(0));
+    (x, y)
+}
+
+/// 将水印文字绘制到图片上，坐标按锚点计算并夹紧在图片范围内。
+fn draw_overlay(img: image::DynamicImage, overlay: &Overlay) -> Result<image::DynamicImage, String> {
+    use ab_glyph::{FontRef, PxScale};
+    use imageproc::drawing::{draw_text_mut, text_size};
+
+    let font = FontRef::try_from_slice(FONT_DATA).map_err(|e| format!("Font load failed: {}", e))?;
+    let scale = PxScale::from(overlay.font_size);
+
+    let mut canvas = img.to_rgba8();
+    let (img_w, img_h) = canvas.dimensions();
+    let (text_w, text_h) = text_size(scale, &font, &overlay.caption);
+
+    let (x, y) = anchor_position(img_w, img_h, text_w, text_h, overlay.anchor);
+
+    let color = image::Rgba(overlay.color);
+    draw_text_mut(&mut canvas, color, x, y, scale, &font, &overlay.caption);
+
+    Ok(image::DynamicImage::ImageRgba8(canvas))
+}
+
+/// `image` crate 负责的编码格式。
+enum ImageFormat {
+    Jpeg(u8),
+    Png,
+}
+
+/// 编码为 WebP：`quality` 为 `Some` 走有损路径，`None` 走无损。
+fn encode_webp(
+    img: &image::DynamicImage,
+    quality: Option<f32>,
+    strip_alpha: bool,
+) -> Result<Vec<u8>, String> {
+    let webp_data = if strip_alpha {
+        let rgb = img.to_rgb8();
+        let (width, height) = rgb.dimensions();
+        let encoder = webp::Encoder::from_rgb(&rgb, width, height);
+        match quality {
+            Some(q) => encoder.encode(q),
+            None => encoder.encode_lossless(),
+        }
+    } else {
+        let rgba = img.to_rgba8();
+        let (width, height) = rgba.dimensions();
+        let encoder = webp::Encoder::from_rgba(&rgba, width, height);
+        match quality {
+            Some(q) => encoder.encode(q),
+            None => encoder.encode_lossless(),
+        }
+    };
     Ok(webp_data.to_vec())
 }
 
-async fn send_response(stream: &mut tokio::net::UnixStream, data: &[u8]) -> std::io::Result<()> {
-    // 状态码 0 = 成功
-    stream.write_all(&[0u8]).await?;
-    // 长度
-    stream.write_all(&(data.len() as u32).to_be_bytes()).await?;
-    // 数据
-    stream.write_all(data).await?;
-    stream.flush().await
+/// 通过 `image` crate 的编码器输出 JPEG / PNG。
+fn encode_image(
+    img: &image::DynamicImage,
+    request: &ConvertRequest,
+    format: ImageFormat,
+) -> Result<Vec<u8>, String> {
+    use image::codecs::{jpeg::JpegEncoder, png::PngEncoder};
+    use image::ImageEncoder;
+
+    let mut out = std::io::Cursor::new(Vec::new());
+    match format {
+        ImageFormat::Jpeg(quality) => {
+            // JPEG 不支持 alpha，统一转 RGB8
+            let rgb = img.to_rgb8();
+            JpegEncoder::new_with_quality(&mut out, quality)
+                .encode_image(&image::DynamicImage::ImageRgb8(rgb))
+                .map_err(|e| format!("Encode failed: {}", e))?;
+        }
+        ImageFormat::Png => {
+            let encoder = PngEncoder::new(&mut out);
+            if request.strip_alpha {
+                let rgb = img.to_rgb8();
+                encoder
+                    .write_image(&rgb, rgb.width(), rgb.height(), image::ColorType::Rgb8.into())
+                    .map_err(|e| format!("Encode failed: {}", e))?;
+            } else {
+                let rgba = img.to_rgba8();
+                encoder
+                    .write_image(&rgba, rgba.width(), rgba.height(), image::ColorType::Rgba8.into())
+                    .map_err(|e| format!("Encode failed: {}", e))?;
+            }
+        }
+    }
+    Ok(out.into_inner())
 }
 
-async fn send_error(stream: &mut tokio::net::UnixStream, msg: &str) -> std::io::Result<()> {
-    // 状态码 1 = 错误
-    stream.write_all(&[1u8]).await?;
+async fn send_response(stream: &mut FramedStream, data: &[u8]) -> std::io::Result<()> {
+    // 一帧 = 状态码 0 (成功) + 数据，长度前缀由编解码器补齐
+    let mut frame = BytesMut::with_capacity(1 + data.len());
+    frame.put_u8(0);
+    frame.put_slice(data);
+    stream.send(frame.freeze()).await
+}
+
+async fn send_error(stream: &mut FramedStream, msg: &str) -> std::io::Result<()> {
+    // 一帧 = 状态码 1 (错误) + 错误信息
     let bytes = msg.as_bytes();
-    stream.write_all(&(bytes.len() as u32).to_be_bytes()).await?;
-    stream.write_all(bytes).await?;
-    stream.flush().await
+    let mut frame = BytesMut::with_capacity(1 + bytes.len());
+    frame.put_u8(1);
+    frame.put_slice(bytes);
+    stream.send(frame.freeze()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 生成一张测试用 PNG：`alpha` 为 true 时带半透明通道。
+    fn sample_png(width: u32, height: u32, alpha: bool) -> Vec<u8> {
+        let img = image::RgbaImage::from_fn(width, height, |x, y| {
+            let a = if alpha { 128 } else { 255 };
+            image::Rgba([(x % 256) as u8, (y % 256) as u8, 64, a])
+        });
+        let mut out = std::io::Cursor::new(Vec::new());
+        image::DynamicImage::ImageRgba8(img)
+            .write_to(&mut out, image::ImageFormat::Png)
+            .unwrap();
+        out.into_inner()
+    }
+
+    fn roundtrip(format: TargetFormat) -> Vec<u8> {
+        let src = sample_png(32, 24, true);
+        let request = ConvertRequest {
+            format,
+            ..Default::default()
+        };
+        process_image(&src, &request).expect("process_image")
+    }
+
+    #[test]
+    fn roundtrip_webp_lossy() {
+        let out = roundtrip(TargetFormat::WebpLossy { quality: 80.0 });
+        assert!(!out.is_empty());
+        let decoded = image::load_from_memory(&out).expect("decode webp");
+        assert_eq!((decoded.width(), decoded.height()), (32, 24));
+    }
+
+    #[test]
+    fn roundtrip_webp_lossless() {
+        let out = roundtrip(TargetFormat::WebpLossless);
+        let decoded = image::load_from_memory(&out).expect("decode webp");
+        assert_eq!((decoded.width(), decoded.height()), (32, 24));
+    }
+
+    #[test]
+    fn roundtrip_jpeg() {
+        let out = roundtrip(TargetFormat::Jpeg { quality: 90 });
+        let decoded = image::load_from_memory(&out).expect("decode jpeg");
+        assert_eq!((decoded.width(), decoded.height()), (32, 24));
+    }
+
+    #[test]
+    fn roundtrip_png() {
+        let out = roundtrip(TargetFormat::Png);
+        let decoded = image::load_from_memory(&out).expect("decode png");
+        assert_eq!((decoded.width(), decoded.height()), (32, 24));
+    }
+
+    #[test]
+    fn resize_only_shrinks() {
+        // max 边界大于原图时不应放大
+        let src = sample_png(32, 24, false);
+        let request = ConvertRequest {
+            max_width: Some(4000),
+            max_height: Some(4000),
+            ..Default::default()
+        };
+        let out = process_image(&src, &request).unwrap();
+        let decoded = image::load_from_memory(&out).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (32, 24));
+    }
+
+    #[test]
+    fn anchor_position_clamped_within_bounds() {
+        // 文字比图片还大时，坐标必须夹到 (0, 0)，不越界
+        let (x, y) = anchor_position(50, 50, 200, 200, Anchor::BottomRight);
+        assert_eq!((x, y), (0, 0));
+
+        // 正常情况下四角/居中都落在可绘制范围内
+        for anchor in [
+            Anchor::TopLeft,
+            Anchor::TopRight,
+            Anchor::BottomLeft,
+            Anchor::BottomRight,
+            Anchor::Center,
+        ] {
+            let (x, y) = anchor_position(200, 100, 40, 20, anchor);
+            assert!(x >= 0 && x <= 200 - 40, "x out of range: {}", x);
+            assert!(y >= 0 && y <= 100 - 20, "y out of range: {}", y);
+        }
+    }
 }